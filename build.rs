@@ -0,0 +1,27 @@
+//! Records build metadata served by the `/version` endpoint.
+
+use std::{env, process::Command};
+
+fn main() {
+    // Missing if not built from a git checkout (e.g. a source archive) or git isn't installed
+    if let Some(commit) = command_output("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=OPA_BUNDLE_BUILDER_GIT_COMMIT={commit}");
+    }
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    if let Some(version) = command_output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=OPA_BUNDLE_BUILDER_RUSTC_VERSION={version}");
+    }
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+/// Runs `program` and returns its trimmed output, if it succeeded.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
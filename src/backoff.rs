@@ -0,0 +1,60 @@
+//! Exponential backoff for retrying failed reconciles.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use rand::Rng;
+
+/// Tracks the number of consecutive failed reconciles per `ConfigMap` and derives how long to wait
+/// before retrying from it.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    failures: Mutex<BTreeMap<String, u32>>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a failed reconcile of the `ConfigMap` `name` and returns the delay before retrying.
+    ///
+    /// The delay starts at the initial delay and doubles with every consecutive failure up to the
+    /// maximum delay. A jitter of ±10% is applied, so that failing `ConfigMap`s don't retry in
+    /// lockstep.
+    pub fn next_delay(&self, name: &str) -> Duration {
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let attempt = failures.entry(name.to_string()).or_default();
+        let delay = self
+            .initial
+            .saturating_mul(2_u32.saturating_pow(*attempt))
+            .min(self.max);
+        *attempt = attempt.saturating_add(1);
+
+        delay
+            .mul_f64(rand::thread_rng().gen_range(0.9..=1.1))
+            .min(self.max)
+    }
+
+    /// Forgets the failures of the `ConfigMap` `name`, e.g. after a successful reconcile.
+    pub fn reset(&self, name: &str) {
+        self.failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name);
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5), Duration::from_secs(300))
+    }
+}
@@ -0,0 +1,161 @@
+//! Compression of bundles.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+/// The algorithm bundles are compressed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Gzip,
+    /// Compresses better at a lower CPU cost than gzip, but is not supported by OPA's bundle loader
+    /// (at the time of writing).
+    Zstd,
+    /// Leaves bundles uncompressed, i.e. plain tar, e.g. for tools that can't decompress them.
+    None,
+}
+
+impl CompressionAlgorithm {
+    /// Parses the name of an algorithm, i.e. `gzip`, `zstd` or `none`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    /// The name of the algorithm, as parsed by [`Self::parse`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::None => "none",
+        }
+    }
+
+    /// Picks gzip for bundles whose files are smaller than `threshold` bytes in total (before
+    /// compression) and zstd for larger ones, which compress considerably faster.
+    pub fn for_size(uncompressed_size: u64, threshold: u64) -> Self {
+        if uncompressed_size < threshold {
+            Self::Gzip
+        } else {
+            Self::Zstd
+        }
+    }
+
+    /// The file name of bundles compressed with this algorithm.
+    pub fn bundle_name(self) -> &'static str {
+        match self {
+            Self::Gzip => "bundle.tar.gz",
+            Self::Zstd => "bundle.tar.zst",
+            Self::None => "bundle.tar",
+        }
+    }
+
+    /// The extension of bundle files compressed with this algorithm, including the `.tar`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => ".tar.gz",
+            Self::Zstd => ".tar.zst",
+            Self::None => ".tar",
+        }
+    }
+
+    /// Returns the algorithm the bundle file `name` is compressed with according to its
+    /// [`Self::extension`], e.g. zstd for `bundle-42.tar.zst`.
+    pub fn of_file_name(name: &str) -> Option<Self> {
+        [Self::Gzip, Self::Zstd, Self::None]
+            .into_iter()
+            .find(|algorithm| name.ends_with(algorithm.extension()))
+    }
+
+    /// The `Content-Encoding` of a tar compressed with this algorithm.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::None => "identity",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Gzip => "application/gzip",
+            Self::Zstd => "application/zstd",
+            Self::None => "application/x-tar",
+        }
+    }
+
+    /// The media type of OCI image layers compressed with this algorithm.
+    pub fn oci_layer_media_type(self) -> &'static str {
+        match self {
+            Self::Gzip => "application/vnd.oci.image.layer.v1.tar+gzip",
+            Self::Zstd => "application/vnd.oci.image.layer.v1.tar+zstd",
+            Self::None => "application/vnd.oci.image.layer.v1.tar",
+        }
+    }
+
+    /// Creates an encoder writing to `file` through a buffer, so that the many small writes of the
+    /// compressor don't each result in a system call. `gzip_level` is ignored for zstd, which
+    /// always uses its default level, and for uncompressed bundles.
+    pub fn encoder(self, file: File, gzip_level: Compression) -> std::io::Result<Encoder> {
+        let file = BufWriter::new(file);
+        Ok(match self {
+            Self::Gzip => Encoder::Gzip(GzEncoder::new(file, gzip_level)),
+            Self::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+            Self::None => Encoder::None(file),
+        })
+    }
+
+    /// Creates a decoder reading a bundle compressed with this algorithm from `reader`.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> std::io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::Gzip => Box::new(GzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Self::None => Box::new(reader),
+        })
+    }
+}
+
+/// Compresses a bundle with one of the [`CompressionAlgorithm`]s.
+pub enum Encoder {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+    None(BufWriter<File>),
+}
+
+impl Encoder {
+    /// Writes the remaining compressed data, flushes the buffer and returns the underlying file.
+    pub fn finish(self) -> std::io::Result<File> {
+        let buffered = match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+            Self::None(buffered) => buffered,
+        };
+        buffered.into_inner().map_err(|error| error.into_error())
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::None(buffered) => buffered.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::None(buffered) => buffered.flush(),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Leader election via a Kubernetes `Lease`, so that only one of multiple replicas builds bundles.
+//!
+//! The algorithm follows the one of client-go: the leader renews the lease regularly, all other
+//! replicas wait for it to expire before taking over.
+
+use std::{future::Future, time::Duration};
+
+use snafu::{ResultExt, Snafu};
+use stackable_operator::{
+    k8s_openapi::{
+        api::coordination::v1::{Lease, LeaseSpec},
+        apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+        chrono::{self, DateTime, Utc},
+    },
+    kube::{api::PostParams, Api},
+};
+use tokio::sync::watch;
+
+/// How long the lease is valid without being renewed.
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+/// How often the leader renews the lease and the other replicas try to acquire it.
+const RETRY_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("unable to get lease {name:?}"))]
+    GetLease {
+        source: stackable_operator::kube::Error,
+        name: String,
+    },
+
+    #[snafu(display("unable to create lease {name:?}"))]
+    CreateLease {
+        source: stackable_operator::kube::Error,
+        name: String,
+    },
+
+    #[snafu(display("unable to update lease {name:?}"))]
+    UpdateLease {
+        source: stackable_operator::kube::Error,
+        name: String,
+    },
+}
+
+pub struct LeaderElection {
+    api: Api<Lease>,
+    /// The name of the `Lease`.
+    name: String,
+    /// Identifies this replica as the holder of the lease, e.g. the name of the `Pod`.
+    identity: String,
+}
+
+impl LeaderElection {
+    pub fn new(api: Api<Lease>, name: String, identity: String) -> Self {
+        Self {
+            api,
+            name,
+            identity,
+        }
+    }
+
+    /// Tries to acquire (or renew) the lease every [`RETRY_PERIOD`] until `shutdown` resolves.
+    ///
+    /// The returned receiver tells whether this replica currently is the leader. On shutdown, the
+    /// lease is released, so that another replica can take over without waiting for it to expire.
+    pub fn run(self, shutdown: impl Future<Output = ()> + Send + 'static) -> watch::Receiver<bool> {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::pin!(shutdown);
+            loop {
+                let is_leader = self.try_acquire_or_renew().await.unwrap_or_else(|error| {
+                    tracing::warn!(
+                        error = &error as &dyn std::error::Error,
+                        "unable to acquire or renew the lease, make sure the service account may get, create and update leases"
+                    );
+                    false
+                });
+                sender.send_replace(is_leader);
+
+                tokio::select! {
+                    () = tokio::time::sleep(RETRY_PERIOD) => {}
+                    () = &mut shutdown => break,
+                }
+            }
+            if *sender.borrow() {
+                if let Err(error) = self.release().await {
+                    tracing::warn!(
+                        error = &error as &dyn std::error::Error,
+                        "unable to release the lease"
+                    );
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Returns `true` if this replica holds the lease afterwards.
+    async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let now = Utc::now();
+        let lease = self
+            .api
+            .get_opt(&self.name)
+            .await
+            .context(GetLeaseSnafu { name: &self.name })?;
+
+        let Some(mut lease) = lease else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.name.clone()),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(self.acquired_spec(now, 0)),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                // Another replica was faster
+                Err(stackable_operator::kube::Error::Api(error)) if error.code == 409 => Ok(false),
+                Err(error) => Err(error).context(CreateLeaseSnafu { name: &self.name }),
+            };
+        };
+
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        if spec.holder_identity.as_deref() == Some(self.identity.as_str()) {
+            spec.renew_time = Some(MicroTime(now));
+            spec.lease_duration_seconds = Some(LEASE_DURATION.as_secs() as i32);
+        } else if is_expired(spec, now) {
+            *spec = self.acquired_spec(now, spec.lease_transitions.unwrap_or(0) + 1);
+        } else {
+            return Ok(false);
+        }
+
+        // The lease carries the resourceVersion it was read with, so only one replica can win
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(stackable_operator::kube::Error::Api(error)) if error.code == 409 => Ok(false),
+            Err(error) => Err(error).context(UpdateLeaseSnafu { name: &self.name }),
+        }
+    }
+
+    /// Gives up the lease, if this replica still holds it.
+    async fn release(&self) -> Result<(), Error> {
+        let Some(mut lease) = self
+            .api
+            .get_opt(&self.name)
+            .await
+            .context(GetLeaseSnafu { name: &self.name })?
+        else {
+            return Ok(());
+        };
+        let Some(spec) = lease
+            .spec
+            .as_mut()
+            .filter(|spec| spec.holder_identity.as_deref() == Some(self.identity.as_str()))
+        else {
+            return Ok(());
+        };
+        spec.holder_identity = None;
+        spec.acquire_time = None;
+        spec.renew_time = None;
+
+        self.api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+            .context(UpdateLeaseSnafu { name: &self.name })?;
+        Ok(())
+    }
+
+    fn acquired_spec(&self, now: DateTime<Utc>, lease_transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+            acquire_time: Some(MicroTime(now)),
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(lease_transitions),
+            ..LeaseSpec::default()
+        }
+    }
+}
+
+/// Returns `true` if the lease is not held by anyone or its holder failed to renew it in time.
+pub fn is_expired(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    match (&spec.holder_identity, &spec.renew_time) {
+        (Some(_), Some(MicroTime(renew_time))) => {
+            let duration = spec
+                .lease_duration_seconds
+                .unwrap_or(LEASE_DURATION.as_secs() as i32);
+            *renew_time + chrono::Duration::seconds(duration.into()) < now
+        }
+        _ => true,
+    }
+}
@@ -0,0 +1,171 @@
+//! Prometheus metrics exposed by the bundle builder at `/metrics`.
+
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::BundleConfig;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Total number of reconciles, successful or not.
+    pub reconciles: IntCounter,
+    /// Failed reconciles, labeled by error `category`.
+    pub reconcile_errors: IntCounterVec,
+    /// Unix timestamp of the last successfully published bundle.
+    pub last_successful_build: Gauge,
+    /// Size of the active bundle in bytes.
+    pub bundle_size_bytes: IntGauge,
+    /// Number of Rego files in the active bundle.
+    pub bundle_rego_files: IntGauge,
+    /// Number of data files (`data.json` and `data.yaml`) in the active bundle.
+    pub bundle_data_files: IntGauge,
+    /// Size of the last bundle that was not published because it exceeded the maximum size.
+    pub rejected_bundle_size_bytes: IntGauge,
+    /// Size of the last bundle built in dry-run mode, i.e. of the bundle that would be published.
+    pub dry_run_bundle_size_bytes: IntGauge,
+    /// Builds that resulted in a bundle identical to the active one, which was therefore kept.
+    pub bundle_noop: IntCounter,
+    /// Builds that resulted in a bundle different from the active one (or the first bundle).
+    pub bundle_changed: IntCounter,
+    /// Uploads of published bundles to S3, labeled by `result` (`success` or `failure`).
+    pub s3_uploads: IntCounterVec,
+    /// Pushes of published bundles to an OCI registry, labeled by `result` (`success` or
+    /// `failure`).
+    pub oci_pushes: IntCounterVec,
+    /// Bundles currently being sent to clients.
+    pub bundle_downloads: IntGauge,
+    /// Published bundles, labeled by the `compression` algorithm they were compressed with.
+    pub published_bundles: IntCounterVec,
+    /// Time spent building (tar + compression) bundles, labeled with the configured
+    /// `compression_level` so that it can be correlated with the build time.
+    pub build_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn new(bundle_config: &BundleConfig) -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let reconciles = IntCounter::new(
+            "opa_bundle_reconciles_total",
+            "Total number of bundle reconciles",
+        )?;
+        let reconcile_errors = IntCounterVec::new(
+            Opts::new(
+                "opa_bundle_reconcile_errors_total",
+                "Total number of failed bundle reconciles",
+            ),
+            &["category"],
+        )?;
+        let last_successful_build = Gauge::new(
+            "opa_bundle_last_successful_build_timestamp_seconds",
+            "Unix timestamp of the last successfully published bundle",
+        )?;
+        let bundle_size_bytes = IntGauge::new(
+            "opa_bundle_size_bytes",
+            "Size of the active bundle in bytes",
+        )?;
+        let bundle_rego_files = IntGauge::new(
+            "opa_bundle_rego_files",
+            "Number of Rego files in the active bundle",
+        )?;
+        let bundle_data_files = IntGauge::new(
+            "opa_bundle_data_files",
+            "Number of data files in the active bundle",
+        )?;
+        let rejected_bundle_size_bytes = IntGauge::new(
+            "opa_bundle_rejected_size_bytes",
+            "Size of the last bundle rejected for exceeding the maximum bundle size",
+        )?;
+        let dry_run_bundle_size_bytes = IntGauge::new(
+            "opa_bundle_dry_run_size_bytes",
+            "Size of the last bundle built in dry-run mode, which was not published",
+        )?;
+        let bundle_noop = IntCounter::new(
+            "opa_bundle_noop_total",
+            "Total number of builds resulting in a bundle identical to the active one",
+        )?;
+        let bundle_changed = IntCounter::new(
+            "opa_bundle_changed_total",
+            "Total number of builds resulting in a bundle different from the active one",
+        )?;
+        let s3_uploads = IntCounterVec::new(
+            Opts::new(
+                "opa_bundle_s3_uploads_total",
+                "Total number of bundle uploads to S3",
+            ),
+            &["result"],
+        )?;
+        let oci_pushes = IntCounterVec::new(
+            Opts::new(
+                "opa_bundle_oci_pushes_total",
+                "Total number of bundle pushes to an OCI registry",
+            ),
+            &["result"],
+        )?;
+        let bundle_downloads = IntGauge::new(
+            "opa_bundle_downloads_in_flight",
+            "Number of bundles currently being sent to clients",
+        )?;
+        let published_bundles = IntCounterVec::new(
+            Opts::new(
+                "opa_bundle_published_total",
+                "Total number of published bundles",
+            ),
+            &["compression"],
+        )?;
+        let build_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "opa_bundle_build_duration_seconds",
+                "Time spent building a bundle",
+            )
+            .const_label(
+                "compression_level",
+                bundle_config.compression.level().to_string(),
+            ),
+        )?;
+
+        registry.register(Box::new(reconciles.clone()))?;
+        registry.register(Box::new(reconcile_errors.clone()))?;
+        registry.register(Box::new(last_successful_build.clone()))?;
+        registry.register(Box::new(bundle_size_bytes.clone()))?;
+        registry.register(Box::new(bundle_rego_files.clone()))?;
+        registry.register(Box::new(bundle_data_files.clone()))?;
+        registry.register(Box::new(rejected_bundle_size_bytes.clone()))?;
+        registry.register(Box::new(dry_run_bundle_size_bytes.clone()))?;
+        registry.register(Box::new(bundle_noop.clone()))?;
+        registry.register(Box::new(bundle_changed.clone()))?;
+        registry.register(Box::new(s3_uploads.clone()))?;
+        registry.register(Box::new(oci_pushes.clone()))?;
+        registry.register(Box::new(bundle_downloads.clone()))?;
+        registry.register(Box::new(published_bundles.clone()))?;
+        registry.register(Box::new(build_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            reconciles,
+            reconcile_errors,
+            last_successful_build,
+            bundle_size_bytes,
+            bundle_rego_files,
+            bundle_data_files,
+            rejected_bundle_size_bytes,
+            dry_run_bundle_size_bytes,
+            bundle_noop,
+            bundle_changed,
+            s3_uploads,
+            oci_pushes,
+            bundle_downloads,
+            published_bundles,
+            build_duration,
+        })
+    }
+
+    /// Encodes all registered metrics in the Prometheus text format.
+    pub fn encode(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
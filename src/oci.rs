@@ -0,0 +1,491 @@
+//! Push of published bundles as OCI artifacts to a registry, for OPA agents pulling their bundles
+//! from a registry instead of from the bundle builder.
+//!
+//! See <https://www.openpolicyagent.org/docs/latest/management-bundles/#oci-registry> and
+//! <https://github.com/opencontainers/distribution-spec/blob/main/spec.md#push>.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::sync::Mutex;
+use warp::{
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE},
+        Method, Request, Response, StatusCode,
+    },
+    hyper::{body::Bytes, client::HttpConnector, Body, Client},
+};
+
+use crate::s3::uri_encode;
+
+/// The media type of the manifest of the artifact.
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// The media type of the (empty) configuration of the artifact.
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+/// How long to wait for the registry (or its token service) to answer a single request.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("unable to build the request to the registry"))]
+    BuildRequest { source: warp::http::Error },
+
+    #[snafu(display("unable to send the request to the registry"))]
+    SendRequest { source: warp::hyper::Error },
+
+    #[snafu(display("the registry did not answer within {TIMEOUT:?}"))]
+    Timeout,
+
+    #[snafu(display("the registry answered {method} {path} with {status}"))]
+    UnexpectedStatus {
+        method: Method,
+        path: String,
+        status: StatusCode,
+    },
+
+    #[snafu(display("the registry did not return the location to upload the blob to"))]
+    MissingUploadLocation,
+
+    #[snafu(display("unable to serialize the manifest"))]
+    SerializeManifest { source: serde_json::Error },
+
+    #[snafu(display("unable to read the token from {realm:?}"))]
+    ReadToken {
+        source: warp::hyper::Error,
+        realm: String,
+    },
+
+    #[snafu(display("unable to parse the token from {realm:?}"))]
+    ParseToken {
+        source: serde_json::Error,
+        realm: String,
+    },
+}
+
+/// Where (and as whom) bundles are pushed to.
+pub struct OciConfig {
+    /// The `host[:port]` of the registry.
+    pub registry: String,
+    /// Whether the registry is reached via plain HTTP instead of HTTPS.
+    pub plain_http: bool,
+    /// The repository within the registry, e.g. `policies/opa-bundle`.
+    pub repository: String,
+    /// The tag the bundle is pushed as, e.g. `latest`.
+    pub tag: String,
+    /// The `username` and `password` used for basic authentication, or to request bearer tokens
+    /// from the token service of the registry, if any.
+    pub credentials: Option<(String, String)>,
+}
+
+/// Pushes bundles to the registry described by an [`OciConfig`].
+#[derive(Clone)]
+pub struct OciSink {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<OciConfig>,
+    /// The bearer token issued by the token service of the registry, reused until it is rejected.
+    token: Arc<RwLock<Option<String>>>,
+    /// Number of pushes started, so that superseded pushes can be skipped.
+    pushes: Arc<AtomicU64>,
+    /// Held while pushing, so that an older bundle never overwrites a newer one.
+    push_lock: Arc<Mutex<()>>,
+}
+
+impl OciSink {
+    pub fn new(config: OciConfig) -> Self {
+        Self {
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+            config: Arc::new(config),
+            token: Arc::new(RwLock::new(None)),
+            pushes: Arc::new(AtomicU64::new(0)),
+            push_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Pushes the bundle `contents` in the background, so that a slow or unavailable registry
+    /// never delays building or serving bundles.
+    ///
+    /// Pushes are best-effort: failures are logged and counted in `results` (labeled with
+    /// `result`), but not retried. If another bundle is published while a previous one is still
+    /// being pushed, only the newest one is pushed afterwards.
+    ///
+    /// The bundle is pushed as layer with the given `media_type`, OPA expects
+    /// `application/vnd.oci.image.layer.v1.tar+gzip`.
+    pub fn push(&self, contents: Bytes, media_type: &'static str, results: IntCounterVec) {
+        let push = self.pushes.fetch_add(1, Ordering::SeqCst) + 1;
+        let sink = self.clone();
+        tokio::spawn(async move {
+            let _push = sink.push_lock.lock().await;
+            if sink.pushes.load(Ordering::SeqCst) != push {
+                tracing::debug!("bundle push superseded by a newer bundle");
+                return;
+            }
+            let config = &sink.config;
+            match sink.push_artifact(contents, media_type).await {
+                Ok(()) => {
+                    results.with_label_values(&["success"]).inc();
+                    tracing::debug!(
+                        registry = %config.registry,
+                        repository = %config.repository,
+                        tag = %config.tag,
+                        "pushed bundle"
+                    );
+                }
+                Err(error) => {
+                    results.with_label_values(&["failure"]).inc();
+                    tracing::warn!(
+                        error = &error as &dyn std::error::Error,
+                        registry = %config.registry,
+                        repository = %config.repository,
+                        tag = %config.tag,
+                        "unable to push bundle"
+                    );
+                }
+            }
+        });
+    }
+
+    /// Pushes the bundle as the only layer of an artifact with an empty configuration.
+    async fn push_artifact(&self, contents: Bytes, media_type: &str) -> Result<(), Error> {
+        let config_blob = Bytes::from_static(b"{}");
+        let config_descriptor = descriptor(CONFIG_MEDIA_TYPE, &config_blob);
+        let layer_descriptor = descriptor(media_type, &contents);
+        self.push_blob(config_blob).await?;
+        self.push_blob(contents).await?;
+
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": config_descriptor,
+            "layers": [layer_descriptor],
+        });
+        let manifest = serde_json::to_vec(&manifest).context(SerializeManifestSnafu)?;
+        let path = format!(
+            "/v2/{}/manifests/{}",
+            self.config.repository, self.config.tag
+        );
+        self.send(
+            Method::PUT,
+            &path,
+            Some(MANIFEST_MEDIA_TYPE),
+            Bytes::from(manifest),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Uploads `blob` in a single request, unless the registry already has it.
+    async fn push_blob(&self, blob: Bytes) -> Result<(), Error> {
+        let digest = digest(&blob);
+        let repository = &self.config.repository;
+        match self
+            .send(
+                Method::HEAD,
+                &format!("/v2/{repository}/blobs/{digest}"),
+                None,
+                Bytes::new(),
+            )
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(Error::UnexpectedStatus {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }) => {}
+            Err(error) => return Err(error),
+        }
+
+        let location = self
+            .send(
+                Method::POST,
+                &format!("/v2/{repository}/blobs/uploads/"),
+                None,
+                Bytes::new(),
+            )
+            .await?
+            .context(MissingUploadLocationSnafu)?;
+        // The location may be absolute, but must point to the registry the credentials are for
+        let location = match location.split_once("://") {
+            Some((_, url)) => url
+                .find('/')
+                .map_or("/", |path_start| &url[path_start..])
+                .to_string(),
+            None => location,
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        self.send(
+            Method::PUT,
+            &format!("{location}{separator}digest={digest}"),
+            Some("application/octet-stream"),
+            blob,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Sends a request for `path` (including the query) to the registry and returns the
+    /// `Location` header of the successful response, if any.
+    ///
+    /// Requests are authenticated with the cached bearer token, or else with basic
+    /// authentication. If the registry challenges the request for a bearer token instead (see
+    /// <https://distribution.github.io/distribution/spec/auth/token/>), a token is requested from
+    /// the token service and the request is sent again.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: Bytes,
+    ) -> Result<Option<String>, Error> {
+        let token = self
+            .token
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let authorization = match (token, &self.config.credentials) {
+            (Some(token), _) => Some(format!("Bearer {token}")),
+            (None, Some((username, password))) => Some(format!(
+                "Basic {}",
+                BASE64.encode(format!("{username}:{password}"))
+            )),
+            (None, None) => None,
+        };
+        let mut response = self
+            .send_once(&method, path, content_type, body.clone(), authorization)
+            .await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|challenge| challenge.to_str().ok())
+                .and_then(parse_bearer_challenge);
+            if let Some(challenge) = challenge {
+                let token = self.request_token(&challenge).await?;
+                *self.token.write().unwrap_or_else(PoisonError::into_inner) = Some(token.clone());
+                response = self
+                    .send_once(
+                        &method,
+                        path,
+                        content_type,
+                        body,
+                        Some(format!("Bearer {token}")),
+                    )
+                    .await?;
+            }
+        }
+
+        let status = response.status();
+        if !status.is_success() {
+            return UnexpectedStatusSnafu {
+                method,
+                path,
+                status,
+            }
+            .fail();
+        }
+        Ok(response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string))
+    }
+
+    /// Sends a single request for `path` to the registry, with the given `Authorization` header.
+    async fn send_once(
+        &self,
+        method: &Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: Bytes,
+        authorization: Option<String>,
+    ) -> Result<Response<Body>, Error> {
+        let scheme = if self.config.plain_http {
+            "http"
+        } else {
+            "https"
+        };
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(format!("{scheme}://{}{path}", self.config.registry));
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let request = request.body(Body::from(body)).context(BuildRequestSnafu)?;
+        tokio::time::timeout(TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| Error::Timeout)?
+            .context(SendRequestSnafu)
+    }
+
+    /// Requests a bearer token from the token service named in `challenge`, authenticated with
+    /// the credentials (if any).
+    async fn request_token(&self, challenge: &BearerChallenge) -> Result<String, Error> {
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+
+        let realm = &challenge.realm;
+        let scope = challenge
+            .scope
+            .clone()
+            .unwrap_or_else(|| format!("repository:{}:pull,push", self.config.repository));
+        let mut query = vec![("scope", scope.as_str())];
+        if let Some(service) = &challenge.service {
+            query.push(("service", service));
+        }
+        let query = query
+            .into_iter()
+            .map(|(key, value)| format!("{key}={}", uri_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let separator = if realm.contains('?') { '&' } else { '?' };
+        let mut request = Request::builder().uri(format!("{realm}{separator}{query}"));
+        if let Some((username, password)) = &self.config.credentials {
+            let credentials = BASE64.encode(format!("{username}:{password}"));
+            request = request.header(AUTHORIZATION, format!("Basic {credentials}"));
+        }
+        let request = request.body(Body::empty()).context(BuildRequestSnafu)?;
+
+        let token = tokio::time::timeout(TIMEOUT, async {
+            let response = self
+                .client
+                .request(request)
+                .await
+                .context(SendRequestSnafu)?;
+            let status = response.status();
+            if !status.is_success() {
+                return UnexpectedStatusSnafu {
+                    method: Method::GET,
+                    path: realm,
+                    status,
+                }
+                .fail();
+            }
+            warp::hyper::body::to_bytes(response.into_body())
+                .await
+                .context(ReadTokenSnafu { realm })
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+        let token =
+            serde_json::from_slice::<TokenResponse>(&token).context(ParseTokenSnafu { realm })?;
+        // Docker Hub and others return both, OAuth 2 compatible services only `access_token`
+        Ok(token.token.or(token.access_token).unwrap_or_default())
+    }
+}
+
+/// The parameters of a `WWW-Authenticate: Bearer ...` challenge.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BearerChallenge {
+    /// The URL of the token service.
+    pub realm: String,
+    pub service: Option<String>,
+    pub scope: Option<String>,
+}
+
+/// Parses a `WWW-Authenticate` header value like
+/// `Bearer realm="https://auth.example.com/token",service="registry",scope="..."`, or returns
+/// `None` if it is no bearer challenge.
+pub fn parse_bearer_challenge(challenge: &str) -> Option<BearerChallenge> {
+    let (scheme, mut params) = challenge.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let (mut realm, mut service, mut scope) = (None, None, None);
+    loop {
+        let (key, rest) = params.trim_start().split_once('=')?;
+        // Values are quoted strings (which may contain commas), or tokens
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')?,
+            None => rest.split_once(',').unwrap_or((rest, "")),
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "realm" => realm = Some(value.to_string()),
+            "service" => service = Some(value.to_string()),
+            "scope" => scope = Some(value.to_string()),
+            _ => {}
+        }
+        params = rest.trim_start().strip_prefix(',').unwrap_or(rest);
+        if params.trim().is_empty() {
+            break;
+        }
+    }
+    Some(BearerChallenge {
+        realm: realm?,
+        service,
+        scope,
+    })
+}
+
+/// The descriptor of `blob` with the given `media_type`, as referenced in manifests.
+fn descriptor(media_type: &str, blob: &[u8]) -> serde_json::Value {
+    serde_json::json!({
+        "mediaType": media_type,
+        "digest": digest(blob),
+        "size": blob.len(),
+    })
+}
+
+/// The digest of `blob`, e.g. `sha256:e3b0...`.
+pub fn digest(blob: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(blob))
+}
+
+/// Looks up the credentials for `registry` in a Docker `config.json`, either given as `auth`
+/// (base64 encoded `username:password`) or as `username` and `password`.
+pub fn docker_config_credentials(config: &[u8], registry: &str) -> Option<(String, String)> {
+    #[derive(Deserialize)]
+    struct DockerConfig {
+        #[serde(default)]
+        auths: std::collections::BTreeMap<String, DockerAuth>,
+    }
+    #[derive(Deserialize)]
+    struct DockerAuth {
+        auth: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+    }
+
+    let config = serde_json::from_slice::<DockerConfig>(config).ok()?;
+    // Registries are listed either by host or by URL, e.g. `http://registry:5000`
+    let auth = config.auths.into_iter().find_map(|(name, auth)| {
+        let host = name.split_once("://").map_or(name.as_str(), |(_, url)| url);
+        (host.trim_end_matches('/') == registry).then_some(auth)
+    })?;
+    match auth {
+        DockerAuth {
+            auth: Some(auth), ..
+        } => {
+            let auth = String::from_utf8(BASE64.decode(auth).ok()?).ok()?;
+            let (username, password) = auth.split_once(':')?;
+            Some((username.to_string(), password.to_string()))
+        }
+        DockerAuth {
+            username: Some(username),
+            password: Some(password),
+            ..
+        } => Some((username, password)),
+        _ => None,
+    }
+}
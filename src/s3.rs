@@ -0,0 +1,250 @@
+//! Upload of published bundles to an S3 compatible object store, for OPA agents pulling their
+//! bundles from S3 instead of from the bundle builder.
+//!
+//! Requests are signed with AWS Signature Version 4, see
+//! <https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html>.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use prometheus::IntCounterVec;
+use sha2::{digest::Output, Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+use stackable_operator::k8s_openapi::chrono::Utc;
+use tokio::sync::Mutex;
+use warp::{
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, HOST},
+        Method, Request, StatusCode, Uri,
+    },
+    hyper::{body::Bytes, client::HttpConnector, Body, Client},
+};
+
+/// How long to wait for the object store to answer an upload.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("unable to build the upload request"))]
+    BuildRequest { source: warp::http::Error },
+
+    #[snafu(display("unable to send the upload request"))]
+    SendRequest { source: warp::hyper::Error },
+
+    #[snafu(display("the object store did not answer within {TIMEOUT:?}"))]
+    Timeout,
+
+    #[snafu(display("the object store answered with {status}"))]
+    UnexpectedStatus { status: StatusCode },
+}
+
+/// Where (and as whom) bundles are uploaded to.
+pub struct S3Config {
+    /// The `http://` or `https://` URL of the object store, e.g. `http://minio:9000`. Buckets are
+    /// addressed path-style, i.e. as `http://minio:9000/<bucket>/<key>`.
+    pub endpoint: Uri,
+    pub region: String,
+    pub bucket: String,
+    /// The key of the bundle object, e.g. `opa/bundle.tar.gz`, see [`S3Sink::key`].
+    pub key: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl S3Config {
+    /// Computes the `Authorization` header of a request signed with AWS Signature Version 4.
+    ///
+    /// `path` must already be URI encoded, see [`uri_encode`]. `headers` are all signed, they must
+    /// be sorted by their lowercase names and include `host` and `x-amz-date` (`amz_date`).
+    pub fn authorization(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        payload_hash: &str,
+        amz_date: &str,
+    ) -> String {
+        let date = &amz_date[..8];
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect::<String>();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request =
+            format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{:x}",
+            Sha256::digest(canonical_request)
+        );
+        let signing_key = [date, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_access_key).into_bytes(),
+            |key, data| hmac_sha256(&key, data.as_bytes()).to_vec(),
+        );
+        let signature = hmac_sha256(&signing_key, string_to_sign.as_bytes());
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature:x}",
+            self.access_key_id
+        )
+    }
+}
+
+/// Uploads bundles to the object store described by an [`S3Config`].
+#[derive(Clone)]
+pub struct S3Sink {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<S3Config>,
+    /// Number of uploads started, so that superseded uploads can be skipped.
+    uploads: Arc<AtomicU64>,
+    /// Held while uploading, so that an older bundle never overwrites a newer one.
+    upload_lock: Arc<Mutex<()>>,
+}
+
+impl S3Sink {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+            config: Arc::new(config),
+            uploads: Arc::new(AtomicU64::new(0)),
+            upload_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The configured key of the bundle object. Bundles may be uploaded to another `key` (e.g.
+    /// one named by their compression algorithm), see [`S3Sink::upload`].
+    pub fn key(&self) -> &str {
+        &self.config.key
+    }
+
+    /// Uploads the bundle `contents` to `key` in the background, so that a slow or unavailable
+    /// object store never delays building or serving bundles.
+    ///
+    /// Uploads are best-effort: failures are logged and counted in `results` (labeled with
+    /// `result`), but not retried, the next published bundle is uploaded again. If another bundle
+    /// is published while a previous one is still being uploaded, only the newest one is uploaded
+    /// afterwards.
+    pub fn upload(
+        &self,
+        contents: Bytes,
+        key: String,
+        content_type: &'static str,
+        results: IntCounterVec,
+    ) {
+        let upload = self.uploads.fetch_add(1, Ordering::SeqCst) + 1;
+        let sink = self.clone();
+        tokio::spawn(async move {
+            let _upload = sink.upload_lock.lock().await;
+            if sink.uploads.load(Ordering::SeqCst) != upload {
+                tracing::debug!("bundle upload superseded by a newer bundle");
+                return;
+            }
+            match sink.put(contents, &key, content_type).await {
+                Ok(()) => {
+                    results.with_label_values(&["success"]).inc();
+                    tracing::debug!(
+                        bucket = %sink.config.bucket,
+                        %key,
+                        "uploaded bundle"
+                    );
+                }
+                Err(error) => {
+                    results.with_label_values(&["failure"]).inc();
+                    tracing::warn!(
+                        error = &error as &dyn std::error::Error,
+                        bucket = %sink.config.bucket,
+                        %key,
+                        "unable to upload bundle"
+                    );
+                }
+            }
+        });
+    }
+
+    async fn put(&self, contents: Bytes, key: &str, content_type: &str) -> Result<(), Error> {
+        let config = &self.config;
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&config.bucket),
+            key.split('/').map(uri_encode).collect::<Vec<_>>().join("/")
+        );
+        let scheme = config.endpoint.scheme_str().unwrap_or("http");
+        let host = config
+            .endpoint
+            .authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or_default();
+        let payload_hash = format!("{:x}", Sha256::digest(&contents));
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = config.authorization(
+            &Method::PUT,
+            &path,
+            &[
+                ("content-type", content_type),
+                ("host", host),
+                ("x-amz-content-sha256", &payload_hash),
+                ("x-amz-date", &amz_date),
+            ],
+            &payload_hash,
+            &amz_date,
+        );
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(format!("{scheme}://{host}{path}"))
+            .header(HOST, host)
+            .header(CONTENT_TYPE, content_type)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header(AUTHORIZATION, authorization)
+            .body(Body::from(contents))
+            .context(BuildRequestSnafu)?;
+        let response = tokio::time::timeout(TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| Error::Timeout)?
+            .context(SendRequestSnafu)?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            UnexpectedStatusSnafu { status }.fail()
+        }
+    }
+}
+
+/// Encodes everything but unreserved characters of `segment`, as required for the canonical
+/// request of AWS Signature Version 4.
+pub fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// HMAC-SHA256 of `data` with `key`.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Output<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes()
+}
@@ -0,0 +1,214 @@
+//! Signing of bundles for OPA's bundle signature verification.
+//!
+//! See <https://www.openpolicyagent.org/docs/latest/management-bundles/#signing>.
+
+use std::{collections::BTreeMap, path::Path};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::Serialize;
+use serde_json::{value::RawValue, Number, Value};
+use sha2::{Digest, Sha256};
+
+/// The names of the files OPA parses before hashing them (see `IsStructuredDoc` in OPA's `bundle`
+/// package). All other files are hashed as they are, even if they contain JSON.
+const STRUCTURED_FILE_NAMES: [&str; 4] =
+    ["data.json", "data.yaml", ".manifest", ".signatures.json"];
+
+/// The key (and its metadata) used to sign bundles.
+pub struct SigningConfig {
+    key: EncodingKey,
+    algorithm: Algorithm,
+    /// Written as `keyid` into the signature, so that OPA can pick the matching verification key.
+    key_id: Option<String>,
+}
+
+impl SigningConfig {
+    /// Loads a PEM encoded private key for `algorithm`, which must be either `RS256` or `ES256`.
+    pub fn new(
+        pem: &[u8],
+        algorithm: Algorithm,
+        key_id: Option<String>,
+    ) -> jsonwebtoken::errors::Result<Self> {
+        let key = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(pem)?,
+            Algorithm::ES256 => EncodingKey::from_ec_pem(pem)?,
+            _ => return Err(jsonwebtoken::errors::ErrorKind::InvalidAlgorithm.into()),
+        };
+        Ok(Self {
+            key,
+            algorithm,
+            key_id,
+        })
+    }
+
+    /// Creates the contents of `.signatures.json` for a bundle consisting of `files`.
+    pub fn sign(&self, files: &[FileHash]) -> jsonwebtoken::errors::Result<Vec<u8>> {
+        let token = jsonwebtoken::encode(
+            &Header::new(self.algorithm),
+            &Claims {
+                files,
+                keyid: self.key_id.as_deref(),
+            },
+            &self.key,
+        )?;
+        Ok(serde_json::to_vec(&Signatures {
+            signatures: vec![token],
+        })?)
+    }
+}
+
+/// The hash of a single file of the bundle, as listed in the signature.
+#[derive(Debug, Serialize)]
+pub struct FileHash {
+    name: String,
+    hash: String,
+    algorithm: &'static str,
+}
+
+impl FileHash {
+    /// Hashes the file at `path` (within the bundle) with the given `contents`.
+    ///
+    /// Like OPA does, data files and the manifest (see [`STRUCTURED_FILE_NAMES`]) are hashed in
+    /// their canonical form (see [`canonical_document`]), all other files as they are.
+    pub fn new(path: &Path, contents: &[u8]) -> Self {
+        let canonical = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| STRUCTURED_FILE_NAMES.contains(name))
+            .and_then(|_| canonical_document(contents));
+
+        Self {
+            name: path.to_string_lossy().into_owned(),
+            hash: format!(
+                "{:x}",
+                Sha256::digest(canonical.as_deref().unwrap_or(contents))
+            ),
+            algorithm: "SHA-256",
+        }
+    }
+}
+
+/// Returns the structured document `contents` (JSON or YAML) in the canonical form OPA hashes:
+/// without whitespace and with the keys of all objects sorted, encoded like Go's `encoding/json`
+/// does (see `bundle/hash.go` in OPA).
+///
+/// Like OPA, valid JSON is parsed as such, keeping numbers as they are written, anything else as
+/// YAML. Returns `None` if `contents` is neither, OPA rejects such bundles anyway.
+fn canonical_document(contents: &[u8]) -> Option<Vec<u8>> {
+    let mut canonical = Vec::new();
+    let json = std::str::from_utf8(contents)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<&RawValue>(contents).ok());
+    match json {
+        Some(json) => write_canonical_json(json, &mut canonical).ok()?,
+        None => write_canonical_value(&serde_yaml::from_slice(contents).ok()?, &mut canonical),
+    }
+    Some(canonical)
+}
+
+fn write_canonical_json(json: &RawValue, canonical: &mut Vec<u8>) -> serde_json::Result<()> {
+    let json = json.get().trim();
+    match json.as_bytes().first() {
+        Some(b'{') => {
+            let object = serde_json::from_str::<BTreeMap<String, &RawValue>>(json)?;
+            canonical.push(b'{');
+            for (index, (key, value)) in object.into_iter().enumerate() {
+                if index > 0 {
+                    canonical.push(b',');
+                }
+                write_string(&key, canonical);
+                canonical.push(b':');
+                write_canonical_json(value, canonical)?;
+            }
+            canonical.push(b'}');
+        }
+        Some(b'[') => {
+            let array = serde_json::from_str::<Vec<&RawValue>>(json)?;
+            canonical.push(b'[');
+            for (index, value) in array.into_iter().enumerate() {
+                if index > 0 {
+                    canonical.push(b',');
+                }
+                write_canonical_json(value, canonical)?;
+            }
+            canonical.push(b']');
+        }
+        Some(b'"') => write_string(&serde_json::from_str::<String>(json)?, canonical),
+        // Numbers as they are written, `true`, `false` and `null`
+        _ => canonical.extend_from_slice(json.as_bytes()),
+    }
+    Ok(())
+}
+
+/// Like [`write_canonical_json`], for documents parsed from YAML.
+fn write_canonical_value(value: &Value, canonical: &mut Vec<u8>) {
+    match value {
+        Value::Object(object) => {
+            let object = object.iter().collect::<BTreeMap<_, _>>();
+            canonical.push(b'{');
+            for (index, (key, value)) in object.into_iter().enumerate() {
+                if index > 0 {
+                    canonical.push(b',');
+                }
+                write_string(key, canonical);
+                canonical.push(b':');
+                write_canonical_value(value, canonical);
+            }
+            canonical.push(b'}');
+        }
+        Value::Array(array) => {
+            canonical.push(b'[');
+            for (index, value) in array.iter().enumerate() {
+                if index > 0 {
+                    canonical.push(b',');
+                }
+                write_canonical_value(value, canonical);
+            }
+            canonical.push(b']');
+        }
+        Value::String(string) => write_string(string, canonical),
+        Value::Number(number) => canonical.extend_from_slice(go_number(number).as_bytes()),
+        other => canonical.extend_from_slice(other.to_string().as_bytes()),
+    }
+}
+
+/// Encodes `string` like Go's `encoding/json` without HTML escaping, which escapes the same
+/// characters as `serde_json` plus U+2028 and U+2029.
+fn write_string(string: &str, canonical: &mut Vec<u8>) {
+    let escaped = Value::from(string)
+        .to_string()
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029");
+    canonical.extend_from_slice(escaped.as_bytes());
+}
+
+/// Formats `number` like Go's `encoding/json`, e.g. `100` for the float `100.0` and `1e+21`.
+fn go_number(number: &Number) -> String {
+    let Some(float) = number.as_f64().filter(|_| number.is_f64()) else {
+        return number.to_string();
+    };
+    let abs = float.abs();
+    if abs != 0.0 && !(1e-6..1e21).contains(&abs) {
+        let formatted = format!("{float:e}");
+        match formatted.split_once('e') {
+            Some((mantissa, exponent)) if !exponent.starts_with('-') => {
+                format!("{mantissa}e+{exponent}")
+            }
+            _ => formatted,
+        }
+    } else {
+        float.to_string()
+    }
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    files: &'a [FileHash],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keyid: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Signatures {
+    signatures: Vec<String>,
+}
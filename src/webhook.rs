@@ -0,0 +1,142 @@
+//! Notification of an external webhook whenever a new bundle has been published, e.g. to trigger
+//! CI/CD pipelines or warm caches.
+
+use std::time::Duration;
+
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use warp::{
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    hyper::{client::HttpConnector, Body, Client},
+};
+
+/// How often a notification is attempted before it is dropped.
+const ATTEMPTS: u32 = 3;
+/// The delay before the first retry, doubled for every further one.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long to wait for the webhook to answer a single attempt.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("unable to build the webhook request"))]
+    BuildRequest { source: warp::http::Error },
+
+    #[snafu(display("unable to serialize the webhook payload"))]
+    SerializePayload { source: serde_json::Error },
+
+    #[snafu(display("unable to send the webhook request"))]
+    SendRequest { source: warp::hyper::Error },
+
+    #[snafu(display("the webhook did not answer within {TIMEOUT:?}"))]
+    Timeout,
+
+    #[snafu(display("the webhook answered with {status}"))]
+    UnexpectedStatus { status: StatusCode },
+}
+
+/// The JSON payload posted to the webhook.
+#[derive(Clone, Debug, Serialize)]
+pub struct BundlePublished {
+    /// The revision of the bundle, as written to its `.manifest`.
+    pub revision: String,
+    /// Hex encoded SHA-256 of the bundle file, i.e. its `ETag`.
+    pub sha256: String,
+    /// Size of the bundle file in bytes.
+    pub size: u64,
+    /// The publish time in RFC 3339 format.
+    pub timestamp: String,
+}
+
+/// Notifies a webhook of published bundles.
+#[derive(Clone)]
+pub struct Webhook {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: Uri,
+    /// Sent as `Authorization` header with every notification, if set.
+    authorization: Option<HeaderValue>,
+}
+
+impl Webhook {
+    pub fn new(url: Uri, authorization: Option<HeaderValue>) -> Self {
+        Self {
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+            url,
+            authorization,
+        }
+    }
+
+    /// Posts `event` to the webhook in the background, so that a slow or failing webhook never
+    /// delays building or serving bundles.
+    ///
+    /// Failed notifications are retried up to [`ATTEMPTS`] times with an exponentially growing
+    /// delay and are logged and dropped afterwards. Notifications of bundles published shortly
+    /// after each other may therefore arrive out of order, receivers should compare the
+    /// revision.
+    pub fn notify(&self, event: BundlePublished) {
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            let mut delay = INITIAL_RETRY_DELAY;
+            for attempt in 1..=ATTEMPTS {
+                match webhook.send(&event).await {
+                    Ok(()) => {
+                        tracing::debug!(revision = %event.revision, "notified webhook");
+                        return;
+                    }
+                    Err(error) if attempt < ATTEMPTS => {
+                        tracing::info!(
+                            error = &error as &dyn std::error::Error,
+                            attempt,
+                            "unable to notify webhook, retrying in {delay:?}"
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                    Err(error) => {
+                        tracing::warn!(
+                            error = &error as &dyn std::error::Error,
+                            revision = %event.revision,
+                            "unable to notify webhook, giving up"
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    async fn send(&self, event: &BundlePublished) -> Result<(), Error> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization.clone());
+        }
+        let request = request
+            .body(Body::from(
+                serde_json::to_vec(event).context(SerializePayloadSnafu)?,
+            ))
+            .context(BuildRequestSnafu)?;
+
+        let response = tokio::time::timeout(TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| Error::Timeout)?
+            .context(SendRequestSnafu)?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            UnexpectedStatusSnafu { status }.fail()
+        }
+    }
+}